@@ -1,7 +1,13 @@
+use std::collections::HashSet;
+use std::env;
 use std::ffi::{OsString, OsStr};
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-#[derive(Clone, Eq, Hash, PartialEq)]
+use rayon::prelude::*;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Stat {
   Link(Link),
   Dir(Dir),
@@ -26,7 +32,7 @@ enum LinkExpansion {
   Loop(String),
 }
 
-#[derive(Clone, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct PathStat {
   // The symbolic name of some filesystem Path, which is context specific.
   pub path: PathBuf,
@@ -34,6 +40,64 @@ pub struct PathStat {
   pub stat: Stat,
 }
 
+impl PathStat {
+  /**
+   * Re-root `self.path` (which is relative to whatever root it was originally globbed from) to
+   * be relative to `base` instead, without touching the filesystem. If `self.path` and `base`
+   * share no leading component, `self.path` is returned unchanged.
+   */
+  pub fn relativize(&self, base: &Path) -> PathBuf {
+    relativize(&self.path, base)
+  }
+}
+
+/**
+ * Relativize a batch of PathStats against a common `base` in one pass.
+ */
+pub fn relativize_all(path_stats: &[PathStat], base: &Path) -> Vec<PathBuf> {
+  path_stats.iter().map(|path_stat| path_stat.relativize(base)).collect()
+}
+
+fn relativize(path: &Path, base: &Path) -> PathBuf {
+  let mut path_components = path.components().peekable();
+  let mut base_components = base.components().peekable();
+
+  let mut common = 0;
+  while path_components.peek().is_some() && path_components.peek() == base_components.peek() {
+    path_components.next();
+    base_components.next();
+    common += 1;
+  }
+
+  if common == 0 {
+    // No common prefix: `path` is returned as it is, without a `..`-walk from `base`.
+    return path.to_path_buf();
+  }
+
+  // Capacity is in bytes, not components: `base` plus `path` covers the usual case (the remaining
+  // components take no more bytes than `path` itself), but a `..`-walk over many one-character
+  // `base` components can outgrow it, and the PathBuf then reallocates.
+  let mut out = PathBuf::with_capacity(base.as_os_str().len() + path.as_os_str().len());
+  for _ in base_components {
+    out.push("..");
+  }
+  for component in path_components {
+    out.push(component.as_os_str());
+  }
+
+  if has_trailing_separator(path) && !has_trailing_separator(&out) {
+    let mut with_separator = out.into_os_string();
+    with_separator.push(::std::path::MAIN_SEPARATOR.to_string());
+    out = PathBuf::from(with_separator);
+  }
+
+  out
+}
+
+fn has_trailing_separator(path: &Path) -> bool {
+  path.as_os_str().to_str().map_or(false, |s| s.ends_with(::std::path::MAIN_SEPARATOR))
+}
+
 #[derive(Clone)]
 pub enum PathGlob {
   Root,
@@ -76,7 +140,32 @@ impl PathGlob {
   }
 }
 
-pub struct PathGlobs(pub Vec<PathGlob>);
+/**
+ * Restricts the `Stat` variants that a glob is permitted to resolve to.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WalkType {
+  Files,
+  Dirs,
+  All,
+}
+
+impl WalkType {
+  fn matches(&self, stat: &Stat) -> bool {
+    match (self, stat) {
+      (&WalkType::All, _) => true,
+      (&WalkType::Files, &Stat::File(_)) => true,
+      (&WalkType::Dirs, &Stat::Dir(_)) => true,
+      (&WalkType::Files, _) | (&WalkType::Dirs, _) => false,
+    }
+  }
+}
+
+pub struct PathGlobs {
+  pub include: Vec<PathGlob>,
+  pub exclude: Vec<PathGlob>,
+  pub walk_type: WalkType,
+}
 
 const SINGLE_STAR: &'static str ="*";
 const DOUBLE_STAR: &'static str = "**";
@@ -91,35 +180,201 @@ fn join(components: &[&OsStr]) -> PathBuf {
   out
 }
 
+/**
+ * The path a Stat's own variant wraps: for a `Link`, this is the link's own (in-tree) location,
+ * not the destination it points to.
+ */
+fn stat_path(stat: &Stat) -> &Path {
+  match stat {
+    &Stat::Link(Link(ref p)) => p,
+    &Stat::Dir(Dir(ref p)) => p,
+    &Stat::File(File(ref p)) => p,
+  }
+}
+
+fn basename(stat: &Stat) -> &OsStr {
+  let path = stat_path(stat);
+  path.file_name().unwrap_or_else(|| path.as_os_str())
+}
+
+/**
+ * Matches a single path component against a wildcard, where `*` in the wildcard matches any
+ * (possibly empty) run of characters.
+ */
+fn matches_wildcard(name: &OsStr, wildcard: &OsStr) -> bool {
+  if wildcard == SINGLE_STAR {
+    return true;
+  }
+  match (name.to_str(), wildcard.to_str()) {
+    (Some(name), Some(wildcard)) => fnmatch(name, wildcard),
+    _ => name == wildcard,
+  }
+}
+
+fn fnmatch(name: &str, pattern: &str) -> bool {
+  let name: Vec<char> = name.chars().collect();
+  let pattern: Vec<char> = pattern.chars().collect();
+
+  // matched[p][n] is true if pattern[..p] matches name[..n].
+  let mut matched = vec![vec![false; name.len() + 1]; pattern.len() + 1];
+  matched[0][0] = true;
+  for p in 1..(pattern.len() + 1) {
+    if pattern[p - 1] == '*' {
+      matched[p][0] = matched[p - 1][0];
+    }
+  }
+  for p in 1..(pattern.len() + 1) {
+    for n in 1..(name.len() + 1) {
+      matched[p][n] = if pattern[p - 1] == '*' {
+        matched[p - 1][n] || matched[p][n - 1]
+      } else {
+        matched[p - 1][n - 1] && pattern[p - 1] == name[n - 1]
+      };
+    }
+  }
+  matched[pattern.len()][name.len()]
+}
+
 impl PathGlobs {
-  pub fn create(relative_to: &Dir, filespecs: Vec<PathBuf>) -> PathGlobs {
-    PathGlobs(
-      filespecs.iter()
-        .flat_map(|filespec| {
-          PathGlobs::parse(relative_to, relative_to.0.as_path(), filespec)
-        })
-        .collect()
-    )
+  /**
+   * Creates a PathGlobs from include and exclude filespecs, relative to a root Dir. Excludes
+   * are parsed the same way as includes: they are themselves PathGlobs, and are expanded and
+   * matched against the include results by `FSContext::apply_path_globs`.
+   */
+  pub fn create(
+    relative_to: &Dir,
+    include: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+    walk_type: WalkType,
+  ) -> PathGlobs {
+    PathGlobs {
+      include: PathGlobs::parse_all(relative_to, &include),
+      exclude: PathGlobs::parse_all(relative_to, &exclude),
+      walk_type: walk_type,
+    }
+  }
+
+  fn parse_all(relative_to: &Dir, filespecs: &[PathBuf]) -> Vec<PathGlob> {
+    filespecs.iter()
+      .flat_map(|filespec| {
+        PathGlobs::parse(relative_to, relative_to.0.as_path(), filespec)
+      })
+      .collect()
   }
 
   /**
    * Eliminate consecutive '**'s to avoid repetitive traversing.
    */
   fn normalize_doublestar(parts: &mut Vec<&OsStr>) {
+    if parts.first().copied() != Some(OsStr::new(DOUBLE_STAR)) {
+      return;
+    }
     let mut idx = 1;
     while idx < parts.len() && DOUBLE_STAR == parts[idx] {
       idx += 1;
     }
-    parts.drain(..idx);
+    parts.drain(1..idx);
+  }
+
+  /**
+   * Expand a leading `~`/`~user` component and any dots-only components (`...`, `....`, etc.,
+   * where `...` means `../..`, `....` means `../../..`, and so on) into a `(canonical_dir,
+   * symbolic_path)` pair to resume parsing from, plus whatever filespec components remain to be
+   * matched against it.
+   *
+   * Unlike the rest of `PathGlob`'s matching, these shorthands can't be satisfied by treating
+   * the expansion as a literal wildcard to match against `scandir` results: `scandir` will never
+   * return an entry literally named `/home/jane` or `..`. Instead, `~`/`~user` re-root
+   * `canonical_dir` directly at the real home directory (there is no FSContext available here to
+   * `stat()` it, but home directories are already absolute paths, so no lookup is needed), and
+   * n-dots walk `canonical_dir` up via `Path::parent`. `symbolic_path` keeps a literal `..` for
+   * each level walked, so that `PathAuditor` can still catch a walk that steps above the
+   * original root.
+   *
+   * Only valid UTF-8 components are considered for expansion, so that no lossy conversion is
+   * ever needed: a non-UTF-8 `~` or dots-only component is passed through unchanged.
+   */
+  fn expand_shorthand(canonical_dir: &Dir, symbolic_path: &Path, filespec: &Path) -> (Dir, PathBuf, PathBuf) {
+    let mut canonical_dir = canonical_dir.clone();
+    let mut symbolic_path = symbolic_path.to_owned();
+    let mut remainder = PathBuf::new();
+
+    for (idx, component) in filespec.components().enumerate() {
+      match component {
+        Component::Normal(os) if idx == 0 && os.to_str().map_or(false, |s| s.starts_with('~')) => {
+          match PathGlobs::expand_tilde(os.to_str().unwrap()) {
+            Some(home) => {
+              canonical_dir = Dir(home);
+              symbolic_path = PathBuf::new();
+            },
+            None => remainder.push(os),
+          }
+        },
+        Component::Normal(os) if PathGlobs::is_dots_shorthand(os) => {
+          // "..." (3 dots) means "../..": one level up per dot beyond the first.
+          for _ in 0..(os.len() - 1) {
+            canonical_dir = Dir(
+              canonical_dir.0.parent().map(|parent| parent.to_owned()).unwrap_or_else(|| canonical_dir.0.clone())
+            );
+            symbolic_path.push("..");
+          }
+        },
+        other => remainder.push(other.as_os_str()),
+      }
+    }
+
+    (canonical_dir, symbolic_path, remainder)
+  }
+
+  fn is_dots_shorthand(os: &OsStr) -> bool {
+    os.to_str().map_or(false, |s| s.len() > 2 && s.chars().all(|c| c == '.'))
+  }
+
+  fn expand_tilde(component: &str) -> Option<PathBuf> {
+    if component == "~" {
+      return env::var_os("HOME").map(PathBuf::from);
+    }
+    PathGlobs::lookup_home_dir(&component[1..])
+  }
+
+  fn lookup_home_dir(user: &str) -> Option<PathBuf> {
+    // Like the bare `~` case above, there is no `FSContext` available here to go through: this
+    // reads the system passwd database directly rather than via the trait. Tests point this at a
+    // fixture file via `_PANTS_TEST_PASSWD_PATH` rather than depending on the real `/etc/passwd`,
+    // mirroring how the `~` case above is tested by overriding `HOME` rather than reading the
+    // real one.
+    let passwd_path =
+      env::var_os("_PANTS_TEST_PASSWD_PATH").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/etc/passwd"));
+    let passwd = fs::read_to_string(passwd_path).ok()?;
+    passwd.lines()
+      .filter_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() > 5 && fields[0] == user {
+          Some(PathBuf::from(fields[5]))
+        } else {
+          None
+        }
+      })
+      .next()
   }
 
   /**
    * Given a filespec String, parse it to a series of PathGlob objects.
    */
   fn parse(canonical_dir: &Dir, symbolic_path: &Path, filespec: &Path) -> Vec<PathGlob> {
-    let mut parts: Vec<&OsStr> = Path::new(filespec).iter().collect();
+    let (canonical_dir, symbolic_path, expanded) =
+      PathGlobs::expand_shorthand(canonical_dir, symbolic_path, filespec);
+    let canonical_dir = &canonical_dir;
+    let symbolic_path = symbolic_path.as_path();
+    let mut parts: Vec<&OsStr> = expanded.iter().collect();
     PathGlobs::normalize_doublestar(&mut parts);
 
+    if parts.is_empty() {
+      // The entire filespec was consumed by shorthand expansion (e.g. a bare `~` or `...`):
+      // match everything directly under the directory it expanded to.
+      return vec![PathGlob::wildcard(canonical_dir.clone(), symbolic_path.to_owned(), OsString::from(SINGLE_STAR))];
+    }
+
     if canonical_dir.0.as_os_str() == "." && parts.len() == 1 && parts[0] == "." {
       // A request for the root path.
       vec![PathGlob::Root]
@@ -192,6 +447,94 @@ impl PathGlobs {
   }
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub struct AuditError(pub String);
+
+/**
+ * Audits PathStats (and the Links they were reached through) as they are produced by globbing,
+ * to ensure that none of them escape a canonical build root `Dir` via a `..` in their symbolic
+ * path, or via a symlink that resolves (directly or transitively) to a target outside the root.
+ *
+ * This is a security property: it ensures that a hermetic build cannot accidentally (or
+ * maliciously) capture files that live outside of the tree it was granted access to.
+ */
+pub struct PathAuditor {
+  root: Dir,
+  // Symbolic paths that have already been audited successfully: a path whose parent is present
+  // here cannot escape via `..` (that was already checked).
+  audited: Mutex<HashSet<PathBuf>>,
+}
+
+impl PathAuditor {
+  pub fn new(root: Dir) -> PathAuditor {
+    PathAuditor {
+      root: root,
+      audited: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /**
+   * Reject a symbolic path that escapes the build root via a `..`. This only ever sees the
+   * Stat that `scandir`/`stat` returned directly (i.e. not yet followed through any symlink), so
+   * it cannot check where a symlink actually leads: see `audit_symlink_target` for that half.
+   */
+  pub fn audit(&self, path: &Path, _stat: &Stat) -> Result<(), AuditError> {
+    // The cache only vouches for a path that extends an audited parent by a normal component: a
+    // trailing `..` (as in `a/../..`) must still be checked.
+    let already_audited =
+      matches!(path.components().next_back(), Some(Component::Normal(_))) &&
+        path.parent().is_some_and(|parent| self.audited.lock().unwrap().contains(parent));
+    if !already_audited {
+      PathAuditor::audit_no_escape_above_root(path)?;
+    }
+
+    self.audited.lock().unwrap().insert(path.to_owned());
+    Ok(())
+  }
+
+  /**
+   * Reject a symlink whose destination (as learned by following it via `FSContext::expand_link`)
+   * resolves outside of the build root. `path` is the symlink's own symbolic path, used only to
+   * produce a readable error; `target` is the canonical path it was actually resolved to.
+   */
+  pub fn audit_symlink_target(&self, path: &Path, target: &Path) -> Result<(), AuditError> {
+    if !target.starts_with(&self.root.0) {
+      return Err(
+        AuditError(
+          format!(
+            "Globbed symlink `{}` resolves to `{}`, which is outside of the build root `{}`.",
+            path.display(),
+            target.display(),
+            self.root.0.display()
+          )
+        )
+      );
+    }
+    Ok(())
+  }
+
+  fn audit_no_escape_above_root(path: &Path) -> Result<(), AuditError> {
+    let mut depth: isize = 0;
+    for component in path.components() {
+      match component {
+        Component::ParentDir => {
+          depth -= 1;
+          if depth < 0 {
+            return Err(
+              AuditError(
+                format!("Globbed path `{}` traverses above the build root via `..`.", path.display())
+              )
+            );
+          }
+        },
+        Component::Normal(_) => depth += 1,
+        _ => {},
+      }
+    }
+    Ok(())
+  }
+}
+
 /**
  * A context for filesystem operations parameterized on a continuation type 'K'. An operation
  * resulting in K indicates that more information is needed to complete the operation.
@@ -201,10 +544,31 @@ pub trait FSContext<K> {
   fn stat(&self, path: &Path) -> Result<Stat, K>;
   fn scandir(&self, dir: &Dir) -> Result<Vec<Stat>, K>;
 
+  /**
+   * Audit a symbolic path and the (not-yet-followed) Stat it resolved to, rejecting ones that
+   * escape the build root via a `..`. Contexts that should enforce this (i.e. most real
+   * filesystem-backed contexts) should override this to delegate to an owned `PathAuditor`; the
+   * default is permissive so that contexts without a meaningful notion of a build root (e.g.
+   * tests) don't need one.
+   */
+  fn audit(&self, _path: &Path, _stat: &Stat) -> Result<(), AuditError> {
+    Ok(())
+  }
+
+  /**
+   * Audit the destination a symlink was actually followed to, rejecting one that resolves
+   * outside of the build root. A symlink's own Stat never reveals its destination (only its own
+   * in-tree path), so this is called separately, once `expand_link` has produced the resolved
+   * Stat. See `audit` for the `..`-escape half of this check.
+   */
+  fn audit_symlink_target(&self, _path: &Path, _target: &Path) -> Result<(), AuditError> {
+    Ok(())
+  }
+
   /**
    * Recursively expand a symlink to an underlying non-link Stat.
    */
-  fn expand_link<T, C: FSContext<T>>(link: &Link, context: &C) -> Result<LinkExpansion, T> {
+  fn expand_link<T, C: FSContext<T> + ?Sized>(link: &Link, context: &C) -> Result<LinkExpansion, T> {
     let mut link: Link = (*link).clone();
     let mut attempts = 0;
     loop {
@@ -233,27 +597,584 @@ pub trait FSContext<K> {
     }
   }
 
+  /**
+   * Scan `canonical_dir` for entries matching `wildcard`, auditing each one and following any
+   * symlink, and return the PathStats it resolves to. Shared by `apply_path_glob` and
+   * `apply_path_glob_par`, which differ only in how they recurse on `DirWildcard`.
+   */
+  fn resolve_wildcard_matches(
+    &self,
+    canonical_dir: &Dir,
+    symbolic_path: &Path,
+    wildcard: &OsStr,
+  ) -> Result<Vec<PathStat>, Vec<K>> {
+    let mut path_stats = Vec::new();
+    for stat in self.scandir(canonical_dir).map_err(|k| vec![k])? {
+      if !matches_wildcard(basename(&stat), wildcard) {
+        continue;
+      }
+      let path = symbolic_path.join(basename(&stat));
+      // Audit the symbolic path for a `..`-escape before doing anything else.
+      if self.audit(&path, &stat).is_err() {
+        continue;
+      }
+      let stat_is_link = matches!(stat, Stat::Link(_));
+      let resolved =
+        match stat {
+          Stat::Link(ref l) => {
+            match Self::expand_link(l, self).map_err(|k| vec![k])? {
+              LinkExpansion::File(f) => Some(Stat::File(f)),
+              LinkExpansion::Dir(d) => Some(Stat::Dir(d)),
+              LinkExpansion::Loop(_) => {
+                // Symlink loops don't resolve to any Stat: drop them.
+                None
+              },
+            }
+          },
+          stat @ Stat::Dir(_) | stat @ Stat::File(_) => Some(stat),
+        };
+      if let Some(resolved) = resolved {
+        // A symlink's real destination is only known once it has been followed: this is
+        // the only point at which an out-of-root symlink can actually be rejected.
+        if stat_is_link && self.audit_symlink_target(&path, stat_path(&resolved)).is_err() {
+          continue;
+        }
+        path_stats.push(PathStat { path: path, stat: resolved });
+      }
+    }
+    Ok(path_stats)
+  }
+
+  /**
+   * Scan `canonical_dir` for child directories matching `wildcard`, auditing each one and
+   * following any symlink, and parse `remainder` against each to produce the next level of
+   * PathGlobs. Shared by `apply_path_glob` and `apply_path_glob_par`.
+   */
+  fn resolve_dir_wildcard_children(
+    &self,
+    canonical_dir: &Dir,
+    symbolic_path: &Path,
+    wildcard: &OsStr,
+    remainder: &Path,
+  ) -> Result<Vec<PathGlob>, Vec<K>> {
+    let mut children = Vec::new();
+    for stat in self.scandir(canonical_dir).map_err(|k| vec![k])? {
+      if !matches_wildcard(basename(&stat), wildcard) {
+        continue;
+      }
+      let name = basename(&stat).to_owned();
+      let child_symbolic_path = symbolic_path.join(&name);
+      // Audit the symbolic path for a `..`-escape before doing anything else.
+      if self.audit(&child_symbolic_path, &stat).is_err() {
+        continue;
+      }
+      let stat_is_link = matches!(stat, Stat::Link(_));
+      let dir =
+        match stat {
+          Stat::Dir(d) => Some(d),
+          Stat::Link(ref l) => {
+            match Self::expand_link(l, self).map_err(|k| vec![k])? {
+              LinkExpansion::Dir(d) => Some(d),
+              LinkExpansion::File(_) | LinkExpansion::Loop(_) => None,
+            }
+          },
+          Stat::File(_) => None,
+        };
+      if let Some(dir) = dir {
+        // A symlink's real destination is only known once it has been followed: this is
+        // the only point at which an out-of-root symlink can actually be rejected.
+        if stat_is_link && self.audit_symlink_target(&child_symbolic_path, &dir.0).is_err() {
+          continue;
+        }
+        // Continue matching the remainder against the matched child directory: this is what
+        // allows a `**` to recurse to arbitrary depth.
+        children.extend(PathGlobs::parse(&dir, &child_symbolic_path, remainder));
+      }
+    }
+    Ok(children)
+  }
+
   /**
    * Apply a PathGlob, returning either PathStats on success (which may not be distinct) or
    * continuations if more information is needed.
+   *
+   * Rather than recursing on `**`, this maintains an explicit work-stack of PathGlobs seeded
+   * with `path_glob`, so that arbitrarily deep recursive globs don't consume call stack.
    */
   fn apply_path_glob(&self, path_glob: &PathGlob) -> Result<Vec<PathStat>, Vec<K>> {
-    match path_glob {
-      &PathGlob::Root =>
-        Ok(vec![PathGlob::root_stat()]),
-      &PathGlob::Wildcard { ref canonical_dir, .. } => {
-        let directory_listing = self.scandir(canonical_dir).map_err(|k| vec![k])?;
-        // TODO: Need to expand any unexpanded Link stats here: the contents
-        // of a Snapshot must always be only Dirs and Files.
-        panic!("TODO: implement filtering of a DirectoryListing.")
-      },
-      &PathGlob::DirWildcard { .. } => {
-        // Compute a DirectoryListing, and filter to Dirs (also, recursively expand symlinks
-        // to determine whether they represent Dirs).
-        let dir_list = panic!("TODO: implement DirectoryListing.");
-        // expand dirs
-        panic!("TODO: implement filtering and expanding a DirectoryListing to Dirs.")
+    let mut stack: Vec<PathGlob> = vec![path_glob.clone()];
+    let mut path_stats: Vec<PathStat> = Vec::new();
+
+    while let Some(path_glob) = stack.pop() {
+      match path_glob {
+        PathGlob::Root => {
+          path_stats.push(PathGlob::root_stat());
+        },
+        PathGlob::Wildcard { canonical_dir, symbolic_path, wildcard } => {
+          path_stats.extend(self.resolve_wildcard_matches(&canonical_dir, &symbolic_path, &wildcard)?);
+        },
+        PathGlob::DirWildcard { canonical_dir, symbolic_path, wildcard, remainder } => {
+          // Continuing to match the remainder against each matched child directory is what
+          // allows a `**` to recurse to arbitrary depth without recursing this method: it's
+          // pushed back onto the explicit work-stack rather than called into directly.
+          stack.extend(self.resolve_dir_wildcard_children(&canonical_dir, &symbolic_path, &wildcard, &remainder)?);
+        },
+      }
+    }
+
+    // `**` can cause the same (path, stat) pair to be produced more than once: de-duplicate.
+    let unique: HashSet<PathStat> = path_stats.into_iter().collect();
+    Ok(unique.into_iter().collect())
+  }
+
+  /**
+   * Like `apply_path_glob`, but fans subdirectories discovered while expanding a `DirWildcard`
+   * (i.e. a `**`) out across the rayon thread pool too, rather than only parallelizing at the
+   * top level. Because this recurses through Rust's call stack to do so (rather than
+   * `apply_path_glob`'s explicit work-stack), it trades away that method's support for
+   * arbitrarily deep `**` trees in exchange for real parallelism at every level of the walk —
+   * an acceptable trade for the directory trees real source repos have.
+   */
+  fn apply_path_glob_par(&self, path_glob: &PathGlob) -> Result<Vec<PathStat>, Vec<K>>
+      where Self: Sync, K: Send {
+    let path_stats = match path_glob {
+      PathGlob::Root => vec![PathGlob::root_stat()],
+      PathGlob::Wildcard { canonical_dir, symbolic_path, wildcard } =>
+        self.resolve_wildcard_matches(canonical_dir, symbolic_path, wildcard)?,
+      PathGlob::DirWildcard { canonical_dir, symbolic_path, wildcard, remainder } => {
+        let children =
+          self.resolve_dir_wildcard_children(canonical_dir, symbolic_path, wildcard, remainder)?;
+        let results: Vec<Result<Vec<PathStat>, Vec<K>>> =
+          children.par_iter().map(|child| self.apply_path_glob_par(child)).collect();
+        let mut path_stats = Vec::new();
+        for result in results {
+          path_stats.extend(result?);
+        }
+        path_stats
       },
+    };
+
+    let unique: HashSet<PathStat> = path_stats.into_iter().collect();
+    Ok(unique.into_iter().collect())
+  }
+
+  /**
+   * Apply a full PathGlobs (a set of include globs, a set of exclude globs, and a WalkType),
+   * expanding each include glob via `apply_path_glob` and then dropping any PathStat that the
+   * WalkType disallows or that an exclude glob also resolves to.
+   */
+  fn apply_path_globs(&self, path_globs: &PathGlobs) -> Result<Vec<PathStat>, Vec<K>> {
+    let mut path_stats = Vec::new();
+    for path_glob in &path_globs.include {
+      path_stats.extend(self.apply_path_glob(path_glob)?);
+    }
+    self.filter_path_stats(path_stats, &path_globs.exclude, path_globs.walk_type)
+  }
+
+  /**
+   * Like `apply_path_globs`, but fans both the independent include globs of a `PathGlobs` and
+   * the subdirectories discovered while expanding each one's `**`s out across a rayon thread
+   * pool, via `apply_path_glob_par`, rather than visiting them one at a time.
+   *
+   * Because the globs are independent of one another, this always runs every one of them (rather
+   * than failing fast on the first error like `apply_path_globs` does), and merges whatever
+   * errors and PathStats they produced before filtering and de-duplicating the successes.
+   */
+  fn par_apply_path_globs(&self, path_globs: &PathGlobs) -> Result<Vec<PathStat>, Vec<K>>
+      where Self: Sync, K: Send {
+    let results: Vec<Result<Vec<PathStat>, Vec<K>>> =
+      path_globs.include
+        .par_iter()
+        .map(|path_glob| self.apply_path_glob_par(path_glob))
+        .collect();
+
+    let mut path_stats = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+      match result {
+        Ok(stats) => path_stats.extend(stats),
+        Err(ks) => errors.extend(ks),
+      }
+    }
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+
+    self.filter_path_stats(path_stats, &path_globs.exclude, path_globs.walk_type)
+  }
+
+  /**
+   * Filter a Vec<PathStat> down to those permitted by the given WalkType and not matched by
+   * any of the given exclude globs, de-duplicating the result.
+   *
+   * Excludes are expanded eagerly into a set of excluded paths; this is fine while exclude
+   * lists stay small, but could be memoized per-context if that stops being true.
+   */
+  fn filter_path_stats(
+    &self,
+    path_stats: Vec<PathStat>,
+    excludes: &[PathGlob],
+    walk_type: WalkType,
+  ) -> Result<Vec<PathStat>, Vec<K>> {
+    let mut excluded_paths: HashSet<PathBuf> = HashSet::new();
+    for exclude in excludes {
+      excluded_paths.extend(self.apply_path_glob(exclude)?.into_iter().map(|path_stat| path_stat.path));
+    }
+
+    let filtered: HashSet<PathStat> = path_stats.into_iter()
+      .filter(|path_stat| walk_type.matches(&path_stat.stat))
+      .filter(|path_stat| !excluded_paths.contains(&path_stat.path))
+      .collect();
+    Ok(filtered.into_iter().collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  /**
+   * A purely in-memory FSContext: `dirs` maps a Dir's path to the Stats it contains, and
+   * `links` maps a Link's path to the path it resolves to (itself looked up via `dirs`/`links`).
+   */
+  struct TestContext {
+    dirs: HashMap<PathBuf, Vec<Stat>>,
+    links: HashMap<PathBuf, PathBuf>,
+  }
+
+  impl TestContext {
+    fn empty() -> TestContext {
+      TestContext { dirs: HashMap::new(), links: HashMap::new() }
+    }
+
+    fn with_dir(mut self, path: &str, entries: Vec<Stat>) -> TestContext {
+      self.dirs.insert(PathBuf::from(path), entries);
+      self
+    }
+
+    fn with_link(mut self, path: &str, target: &str) -> TestContext {
+      self.links.insert(PathBuf::from(path), PathBuf::from(target));
+      self
+    }
+  }
+
+  impl FSContext<String> for TestContext {
+    fn read_link(&self, link: &Link) -> Result<PathBuf, String> {
+      self.links.get(&link.0).cloned().ok_or_else(|| format!("No such link: {:?}", link.0))
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat, String> {
+      if self.links.contains_key(path) {
+        Ok(Stat::Link(Link(path.to_owned())))
+      } else if self.dirs.contains_key(path) {
+        Ok(Stat::Dir(Dir(path.to_owned())))
+      } else {
+        Ok(Stat::File(File(path.to_owned())))
+      }
+    }
+
+    fn scandir(&self, dir: &Dir) -> Result<Vec<Stat>, String> {
+      self.dirs.get(&dir.0).cloned().ok_or_else(|| format!("No such dir: {:?}", dir.0))
+    }
+  }
+
+  fn root() -> Dir {
+    Dir(PathBuf::from("/root"))
+  }
+
+  fn path_globs(include: Vec<&str>, exclude: Vec<&str>, walk_type: WalkType) -> PathGlobs {
+    PathGlobs::create(
+      &root(),
+      include.into_iter().map(PathBuf::from).collect(),
+      exclude.into_iter().map(PathBuf::from).collect(),
+      walk_type
+    )
+  }
+
+  #[test]
+  fn walk_type_all_matches_every_stat() {
+    assert!(WalkType::All.matches(&Stat::File(File(PathBuf::from("a")))));
+    assert!(WalkType::All.matches(&Stat::Dir(Dir(PathBuf::from("a")))));
+  }
+
+  #[test]
+  fn walk_type_files_only_matches_files() {
+    assert!(WalkType::Files.matches(&Stat::File(File(PathBuf::from("a")))));
+    assert!(!WalkType::Files.matches(&Stat::Dir(Dir(PathBuf::from("a")))));
+  }
+
+  #[test]
+  fn walk_type_dirs_only_matches_dirs() {
+    assert!(WalkType::Dirs.matches(&Stat::Dir(Dir(PathBuf::from("a")))));
+    assert!(!WalkType::Dirs.matches(&Stat::File(File(PathBuf::from("a")))));
+  }
+
+  #[test]
+  fn apply_path_globs_walk_type_files_drops_dirs() {
+    let context =
+      TestContext::empty()
+        .with_dir("/root", vec![
+          Stat::File(File(PathBuf::from("/root/a.txt"))),
+          Stat::Dir(Dir(PathBuf::from("/root/subdir"))),
+        ]);
+    let path_globs = path_globs(vec!["*"], vec![], WalkType::Files);
+
+    let result = context.apply_path_globs(&path_globs).unwrap();
+
+    assert_eq!(result, vec![PathStat { path: PathBuf::from("/root/a.txt"), stat: Stat::File(File(PathBuf::from("/root/a.txt"))) }]);
+  }
+
+  #[test]
+  fn apply_path_globs_exclude_drops_matching_paths() {
+    let context =
+      TestContext::empty()
+        .with_dir("/root", vec![
+          Stat::File(File(PathBuf::from("/root/keep.rs"))),
+          Stat::File(File(PathBuf::from("/root/drop.pyc"))),
+        ]);
+    let path_globs = path_globs(vec!["*"], vec!["*.pyc"], WalkType::All);
+
+    let result = context.apply_path_globs(&path_globs).unwrap();
+
+    assert_eq!(result, vec![PathStat { path: PathBuf::from("/root/keep.rs"), stat: Stat::File(File(PathBuf::from("/root/keep.rs"))) }]);
+  }
+
+  #[test]
+  fn fnmatch_single_star_is_match_all() {
+    assert!(matches_wildcard(OsStr::new("anything.rs"), OsStr::new("*")));
+  }
+
+  #[test]
+  fn fnmatch_exact_match() {
+    assert!(matches_wildcard(OsStr::new("foo.rs"), OsStr::new("foo.rs")));
+    assert!(!matches_wildcard(OsStr::new("foo.rs"), OsStr::new("bar.rs")));
+  }
+
+  #[test]
+  fn fnmatch_embedded_star() {
+    assert!(matches_wildcard(OsStr::new("foo.rs"), OsStr::new("*.rs")));
+    assert!(matches_wildcard(OsStr::new("foo.rs"), OsStr::new("foo.*")));
+    assert!(matches_wildcard(OsStr::new("foo.rs"), OsStr::new("f*o.rs")));
+    assert!(!matches_wildcard(OsStr::new("foo.rs"), OsStr::new("*.pyc")));
+  }
+
+  #[test]
+  fn fnmatch_empty_run_matches_star() {
+    assert!(matches_wildcard(OsStr::new("foo"), OsStr::new("foo*")));
+    assert!(matches_wildcard(OsStr::new(""), OsStr::new("*")));
+    assert!(!matches_wildcard(OsStr::new(""), OsStr::new("?")));
+  }
+
+  #[test]
+  fn path_auditor_allows_paths_within_root() {
+    let auditor = PathAuditor::new(Dir(PathBuf::from("/root")));
+    let result =
+      auditor.audit(
+        Path::new("src/main.rs"),
+        &Stat::File(File(PathBuf::from("/root/src/main.rs")))
+      );
+    assert!(result.is_ok());
+  }
+
+  #[test]
+  fn path_auditor_rejects_dotdot_escaping_above_root() {
+    let auditor = PathAuditor::new(Dir(PathBuf::from("/root")));
+    let result =
+      auditor.audit(
+        Path::new("../outside.rs"),
+        &Stat::File(File(PathBuf::from("/outside.rs")))
+      );
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn path_auditor_rejects_symlink_target_escaping_root() {
+    let auditor = PathAuditor::new(Dir(PathBuf::from("/root")));
+    let result = auditor.audit_symlink_target(Path::new("link"), Path::new("/elsewhere/secret"));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn path_auditor_allows_symlink_target_within_root() {
+    let auditor = PathAuditor::new(Dir(PathBuf::from("/root")));
+    let result = auditor.audit_symlink_target(Path::new("link"), Path::new("/root/target"));
+    assert!(result.is_ok());
+  }
+
+  /**
+   * An `AuditedTestContext` wires a `TestContext`'s in-memory filesystem to a real `PathAuditor`,
+   * the way any actual filesystem-backed context would: unlike calling `PathAuditor::audit`
+   * directly with a hand-built `Stat::Link`, this exercises `apply_path_glob`'s real
+   * scandir-then-`expand_link`-then-audit sequence end to end.
+   */
+  struct AuditedTestContext {
+    inner: TestContext,
+    auditor: PathAuditor,
+  }
+
+  impl FSContext<String> for AuditedTestContext {
+    fn read_link(&self, link: &Link) -> Result<PathBuf, String> {
+      self.inner.read_link(link)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat, String> {
+      self.inner.stat(path)
+    }
+
+    fn scandir(&self, dir: &Dir) -> Result<Vec<Stat>, String> {
+      self.inner.scandir(dir)
+    }
+
+    fn audit(&self, path: &Path, stat: &Stat) -> Result<(), AuditError> {
+      self.auditor.audit(path, stat)
+    }
+
+    fn audit_symlink_target(&self, path: &Path, target: &Path) -> Result<(), AuditError> {
+      self.auditor.audit_symlink_target(path, target)
     }
   }
+
+  #[test]
+  fn apply_path_glob_drops_symlink_escaping_root() {
+    let context = AuditedTestContext {
+      inner:
+        TestContext::empty()
+          .with_dir("/root", vec![Stat::Link(Link(PathBuf::from("/root/escape")))])
+          .with_link("/root/escape", "/outside/secret"),
+      auditor: PathAuditor::new(root()),
+    };
+    let path_globs = path_globs(vec!["*"], vec![], WalkType::All);
+
+    let result = context.apply_path_globs(&path_globs).unwrap();
+
+    assert_eq!(result, vec![]);
+  }
+
+  #[test]
+  fn apply_path_glob_follows_symlink_within_root() {
+    let context = AuditedTestContext {
+      inner:
+        TestContext::empty()
+          .with_dir("/root", vec![Stat::Link(Link(PathBuf::from("/root/alias")))])
+          .with_link("/root/alias", "/root/real.rs"),
+      auditor: PathAuditor::new(root()),
+    };
+    let path_globs = path_globs(vec!["*"], vec![], WalkType::All);
+
+    let result = context.apply_path_globs(&path_globs).unwrap();
+
+    assert_eq!(
+      result,
+      vec![PathStat { path: PathBuf::from("/root/alias"), stat: Stat::File(File(PathBuf::from("/root/real.rs"))) }]
+    );
+  }
+
+  #[test]
+  fn expand_shorthand_leading_tilde_rerootes_canonical_dir() {
+    // Safety: this test does not run concurrently with anything else that reads `HOME`.
+    unsafe { env::set_var("HOME", "/home/jane") };
+
+    let (canonical_dir, symbolic_path, remainder) =
+      PathGlobs::expand_shorthand(&root(), Path::new(""), Path::new("~/src/**"));
+
+    assert_eq!(canonical_dir, Dir(PathBuf::from("/home/jane")));
+    assert_eq!(symbolic_path, PathBuf::new());
+    assert_eq!(remainder, PathBuf::from("src/**"));
+  }
+
+  #[test]
+  fn expand_shorthand_leading_tilde_user_rerootes_canonical_dir() {
+    let passwd_path = env::temp_dir().join(format!("fs_rs_test_passwd_{:?}", std::thread::current().id()));
+    fs::write(&passwd_path, "jane:x:1000:1000:Jane:/home/jane:/bin/bash\nbob:x:1001:1001:Bob:/home/bob:/bin/bash\n")
+      .unwrap();
+    // Safety: this test does not run concurrently with anything else that reads this var.
+    unsafe { env::set_var("_PANTS_TEST_PASSWD_PATH", &passwd_path) };
+
+    let (canonical_dir, symbolic_path, remainder) =
+      PathGlobs::expand_shorthand(&root(), Path::new(""), Path::new("~bob/src/**"));
+
+    unsafe { env::remove_var("_PANTS_TEST_PASSWD_PATH") };
+    fs::remove_file(&passwd_path).unwrap();
+
+    assert_eq!(canonical_dir, Dir(PathBuf::from("/home/bob")));
+    assert_eq!(symbolic_path, PathBuf::new());
+    assert_eq!(remainder, PathBuf::from("src/**"));
+  }
+
+  #[test]
+  fn expand_shorthand_leaves_non_leading_tilde_alone() {
+    let (canonical_dir, _symbolic_path, remainder) =
+      PathGlobs::expand_shorthand(&root(), Path::new(""), Path::new("src/~not-a-home/foo"));
+
+    assert_eq!(canonical_dir, root());
+    assert_eq!(remainder, PathBuf::from("src/~not-a-home/foo"));
+  }
+
+  #[test]
+  fn expand_shorthand_ndots_walk_canonical_dir_up() {
+    let deep = Dir(PathBuf::from("/root/a/b/c"));
+
+    let (canonical_dir, symbolic_path, remainder) =
+      PathGlobs::expand_shorthand(&deep, Path::new("a/b/c"), Path::new(".../tests"));
+
+    // "..." (3 dots) means two levels up.
+    assert_eq!(canonical_dir, Dir(PathBuf::from("/root/a")));
+    assert_eq!(symbolic_path, PathBuf::from("a/b/c/../.."));
+    assert_eq!(remainder, PathBuf::from("tests"));
+  }
+
+  #[test]
+  fn expand_shorthand_ndots_past_filesystem_root_clamps() {
+    let shallow = Dir(PathBuf::from("/a"));
+
+    let (canonical_dir, _symbolic_path, _remainder) =
+      PathGlobs::expand_shorthand(&shallow, Path::new(""), Path::new(".../tests"));
+
+    // There is no further `..` to take once we're already at the filesystem root.
+    assert_eq!(canonical_dir, Dir(PathBuf::from("/")));
+  }
+
+  #[test]
+  fn relativize_descends_into_base() {
+    let path_stat = PathStat { path: PathBuf::from("a/b/c.txt"), stat: Stat::File(File(PathBuf::from("/root/a/b/c.txt"))) };
+
+    assert_eq!(path_stat.relativize(Path::new("a")), PathBuf::from("b/c.txt"));
+  }
+
+  #[test]
+  fn relativize_walks_up_via_dotdot() {
+    let path_stat = PathStat { path: PathBuf::from("a/b/d/e.txt"), stat: Stat::File(File(PathBuf::from("/root/a/b/d/e.txt"))) };
+
+    assert_eq!(path_stat.relativize(Path::new("a/b/c")), PathBuf::from("../d/e.txt"));
+  }
+
+  #[test]
+  fn relativize_no_common_prefix_returns_target_unchanged() {
+    let path_stat = PathStat { path: PathBuf::from("a/b.txt"), stat: Stat::File(File(PathBuf::from("/root/a/b.txt"))) };
+
+    assert_eq!(path_stat.relativize(Path::new("x/y")), PathBuf::from("a/b.txt"));
+  }
+
+  #[test]
+  fn relativize_preserves_trailing_separator() {
+    let path_stat = PathStat { path: PathBuf::from("a/b/"), stat: Stat::Dir(Dir(PathBuf::from("/root/a/b"))) };
+
+    let relativized = path_stat.relativize(Path::new("a"));
+
+    assert!(has_trailing_separator(&relativized));
+  }
+
+  #[test]
+  fn relativize_all_relativizes_each_entry() {
+    let path_stats = vec![
+      PathStat { path: PathBuf::from("a/one.txt"), stat: Stat::File(File(PathBuf::from("/root/a/one.txt"))) },
+      PathStat { path: PathBuf::from("a/two.txt"), stat: Stat::File(File(PathBuf::from("/root/a/two.txt"))) },
+    ];
+
+    assert_eq!(
+      relativize_all(&path_stats, Path::new("a")),
+      vec![PathBuf::from("one.txt"), PathBuf::from("two.txt")]
+    );
+  }
 }